@@ -1,10 +1,13 @@
 extern crate backoff;
 #[macro_use]
 extern crate clap;
+extern crate ctrlc;
 extern crate custom_error;
 extern crate redis;
 extern crate serde;
 extern crate serde_json;
+extern crate tiny_http;
+extern crate uuid;
 #[macro_use]
 extern crate log;
 
@@ -21,9 +24,27 @@ use std::num::ParseIntError;
 use std::path::Path;
 use std::process;
 use std::time::Duration;
+use uuid::Uuid;
 
 /// Redis key under which the set of all registered service keys resides.
 static SERVICE_KEY: &str = "prometheus_sd_service_keys";
+/// Redis key holding the current leader's token, for HA leader election.
+static LOCK_KEY: &str = "prometheus_sd:discover_leader";
+/// Lowest accepted `--debounce-ms` value. `Connection::set_read_timeout`
+/// forwards to the underlying socket, which panics on a zero-duration
+/// timeout, so `0` (the value someone wanting "no debounce" would reach for)
+/// gets clamped up to this instead of taking the whole process down.
+static MIN_DEBOUNCE_MS: u64 = 1;
+/// Lowest accepted `--lock-ttl-ms` value. `monitor_registry_session` derives
+/// its pubsub poll interval as `lock.ttl_ms / 3`, which hits the same
+/// zero-duration `set_read_timeout` panic as `MIN_DEBOUNCE_MS` guards against
+/// once `lock-ttl-ms` drops below 3.
+static MIN_LOCK_TTL_MS: u64 = 3;
+/// Bound on a single "best effort" reconnect attempt, used where a long
+/// `--max-timeout`-style backoff would stall something time-sensitive:
+/// `serve_targets`' single-threaded request loop, and the shutdown handler's
+/// attempt to release the HA lock before the process exits.
+static SHORT_RECONNECT_TIMEOUT_SEC: u64 = 5;
 static DISCOVER_HELP: &str = "Discover services in the environment.
 
 This is a long-running process that will continously monitor Redis for the
@@ -31,6 +52,14 @@ registration of new services and, upon any modifications to the service
 registry, write the services as JSON to an output path where it can be picked
 up by Prometheus' file-based discovery process.
 ";
+static SERVE_HELP: &str = "Serve discovered targets over HTTP.
+
+This is a long-running process that answers requests for a configurable path
+with the current set of discovered services as JSON, in the format expected
+by Prometheus' `http_sd_config`. Unlike `discover`, this doesn't require the
+scraper and discoverer to share a filesystem, which makes it the better fit
+for containerized deployments.
+";
 
 // Custom error and result types that wrap various errors that can arise during
 // service registration and monitoring
@@ -41,6 +70,8 @@ custom_error! {CliError
     InvalidPort{source: ParseIntError}   = "Invalid port number: {source}",
     NoSuchService{service: String}       = "No such service registered: '{service}'",
     NoSuchHost{service: String, host: String} = "No host starting with '{host}' registered for {service}",
+    ServerError{message: String}         = "Problem running HTTP server: {message}",
+    SignalHandlerError{message: String}  = "Problem registering shutdown handler: {message}",
 }
 type Result<T> = std::result::Result<T, CliError>;
 
@@ -62,10 +93,89 @@ struct RegisteredService {
     targets: HashSet<String>,
 }
 
-/// Register a new service instance
-fn register_instance(con: &mut redis::Connection, inst: &ServiceInstance) -> Result<()> {
+/// A Redlock-style distributed lock used to elect a single writer among
+/// several `discover` replicas, so they can run redundantly for HA without
+/// fighting over the same output file.
+#[derive(Clone)]
+struct LeaderLock {
+    token: String,
+    ttl_ms: u64,
+}
+
+impl LeaderLock {
+    fn new(ttl_ms: u64) -> Self {
+        LeaderLock {
+            token: Uuid::new_v4().to_string(),
+            ttl_ms,
+        }
+    }
+
+    /// Try to claim leadership. Succeeds only if nobody else currently holds it.
+    fn try_claim(&self, con: &mut redis::Connection) -> Result<bool> {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(LOCK_KEY)
+            .arg(&self.token)
+            .arg("NX")
+            .arg("PX")
+            .arg(self.ttl_ms)
+            .query(con)?;
+        Ok(claimed.is_some())
+    }
+
+    /// Refresh the lock's TTL, but only if we're still the holder.
+    fn refresh(&self, con: &mut redis::Connection) -> Result<bool> {
+        let refreshed: i32 = redis::Script::new(
+            "if redis.call('get', KEYS[1]) == ARGV[1] then \
+               return redis.call('pexpire', KEYS[1], ARGV[2]) \
+             else \
+               return 0 \
+             end",
+        )
+        .key(LOCK_KEY)
+        .arg(&self.token)
+        .arg(self.ttl_ms)
+        .invoke(con)?;
+        Ok(refreshed == 1)
+    }
+
+    /// Release the lock, but only if we're still the holder (compare-and-delete).
+    fn release(&self, con: &mut redis::Connection) -> Result<()> {
+        let _: i32 = redis::Script::new(
+            "if redis.call('get', KEYS[1]) == ARGV[1] then \
+               return redis.call('del', KEYS[1]) \
+             else \
+               return 0 \
+             end",
+        )
+        .key(LOCK_KEY)
+        .arg(&self.token)
+        .invoke(con)?;
+        Ok(())
+    }
+}
+
+/// Redis key of the liveness marker for `target` of `service`. A target is
+/// only considered live by `discover_services` while this key exists; it is
+/// set without a TTL for permanent registrations, or with one for `--ttl`
+/// registrations that need to be refreshed via `heartbeat`.
+fn target_marker_key(service: &str, target: &str) -> String {
+    format!("prometheus_sd:{}:target:{}", service, target)
+}
+
+/// Register a new service instance.
+///
+/// `ttl`, if set, makes the registration expire after that many seconds
+/// unless refreshed via the `heartbeat` subcommand, so that instances which
+/// crash without calling `unregister` are cleaned up automatically.
+fn register_instance(
+    con: &mut redis::Connection,
+    inst: &ServiceInstance,
+    ttl: Option<u64>,
+) -> Result<()> {
     let labels_key = format!("prometheus_sd:{}:labels", inst.service_name);
     let targets_key = format!("prometheus_sd:{}:targets", inst.service_name);
+    let target = format!("{}:{}{}", inst.host, inst.port, inst.metrics_path);
+    let marker_key = target_marker_key(&inst.service_name, &target);
     let mut pipe = redis::pipe();
     pipe.atomic()
         .sadd(SERVICE_KEY, &inst.service_name)
@@ -73,11 +183,12 @@ fn register_instance(con: &mut redis::Connection, inst: &ServiceInstance) -> Res
     if !inst.labels.is_empty() {
         pipe.hset_multiple(&labels_key, &inst.labels);
     }
-    pipe.sadd(
-        targets_key,
-        format!("{}:{}{}", inst.host, inst.port, inst.metrics_path),
-    )
-    .query(con)?;
+    pipe.sadd(&targets_key, &target);
+    match ttl {
+        Some(ttl) => pipe.set_ex(&marker_key, 1, ttl as usize),
+        None => pipe.set(&marker_key, 1),
+    };
+    pipe.query(con)?;
     Ok(())
 }
 
@@ -109,6 +220,7 @@ fn unregister_instance(
                 .find(|t| t.starts_with(&host))
                 .map(|t| {
                     pipe.srem(&targets_key, &t);
+                    pipe.del(target_marker_key(service_name, t));
                 })
                 .is_some();
             if !found {
@@ -125,6 +237,9 @@ fn unregister_instance(
         }
         None => {
             // Remove everything relating to the service, i.e. all labels and all targets
+            for target in &service_targets {
+                pipe.del(target_marker_key(service_name, target));
+            }
             pipe.del(&targets_key);
             pipe.del(&labels_key);
             pipe.srem(&SERVICE_KEY, &service_name);
@@ -134,33 +249,236 @@ fn unregister_instance(
     Ok(())
 }
 
+/// Periodically refresh a `--ttl` registration's liveness marker so it
+/// doesn't get pruned as expired. Runs forever, conceptually like flodgatt's
+/// periodic `send_pings`.
+fn heartbeat(
+    client: &redis::Client,
+    max_timeout_sec: u64,
+    service_name: &str,
+    target: &str,
+    ttl: u64,
+    interval: Duration,
+) -> Result<()> {
+    loop {
+        if let Err(e) =
+            heartbeat_session(client, max_timeout_sec, service_name, target, ttl, interval)
+        {
+            warn!(
+                "Lost connection to Redis while sending heartbeats ({}), reconnecting...",
+                e
+            );
+        }
+    }
+}
+
+/// Run a single heartbeat session, for as long as the connection stays up.
+/// `heartbeat` above reconnects with the usual backoff policy once this
+/// errors out, so a transient Redis blip doesn't let a live instance's TTL
+/// marker silently lapse and get pruned as dead.
+fn heartbeat_session(
+    client: &redis::Client,
+    max_timeout_sec: u64,
+    service_name: &str,
+    target: &str,
+    ttl: u64,
+    interval: Duration,
+) -> Result<()> {
+    let mut con = try_redis_connect(client, max_timeout_sec)?;
+    let marker_key = target_marker_key(service_name, target);
+    loop {
+        redis::cmd("SET")
+            .arg(&marker_key)
+            .arg(1)
+            .arg("EX")
+            .arg(ttl)
+            .query(&mut con)?;
+        std::thread::sleep(interval);
+    }
+}
+
 /// Discover all services with their hosts and labels in the registry.
 fn discover_services(con: &mut redis::Connection) -> Result<Vec<RegisteredService>> {
     let mut service_keys: Vec<String> = con.smembers(SERVICE_KEY)?;
     service_keys.sort();
-    service_keys
-        .iter()
-        .map(|key| {
-            Ok(RegisteredService {
-                labels: con.hgetall(format!("prometheus_sd:{}:labels", key))?,
-                targets: con.smembers(format!("prometheus_sd:{}:targets", key))?,
-            })
-        })
-        .collect()
+    let mut services = Vec::with_capacity(service_keys.len());
+    for key in &service_keys {
+        let all_targets: HashSet<String> =
+            con.smembers(format!("prometheus_sd:{}:targets", key))?;
+        // A target only counts as live while its marker key exists, so that
+        // instances whose `--ttl` lapsed without a heartbeat drop out
+        // without needing an explicit `unregister`.
+        let targets = filter_live_targets(con, key, all_targets)?;
+        services.push(RegisteredService {
+            labels: con.hgetall(format!("prometheus_sd:{}:labels", key))?,
+            targets,
+        });
+    }
+    Ok(services)
+}
+
+/// Filter `targets` down to the ones whose liveness marker still exists.
+///
+/// This is on the hot path: `discover_services` runs on every debounced
+/// registry event and on every `serve` request, so the `EXISTS` checks are
+/// batched into a single pipelined round-trip per service rather than one
+/// command per target.
+fn filter_live_targets(
+    con: &mut redis::Connection,
+    service: &str,
+    targets: HashSet<String>,
+) -> Result<HashSet<String>> {
+    if targets.is_empty() {
+        return Ok(targets);
+    }
+    let targets: Vec<String> = targets.into_iter().collect();
+    let mut pipe = redis::pipe();
+    for target in &targets {
+        pipe.exists(target_marker_key(service, target));
+    }
+    let alive: Vec<bool> = pipe.query(con)?;
+    Ok(targets
+        .into_iter()
+        .zip(alive)
+        .filter_map(|(target, alive)| if alive { Some(target) } else { None })
+        .collect())
+}
+
+/// Remove targets whose TTL marker has expired, along with any service left
+/// without any remaining targets (its labels and `SERVICE_KEY` membership).
+fn prune_expired_targets(con: &mut redis::Connection) -> Result<()> {
+    let service_keys: Vec<String> = con.smembers(SERVICE_KEY)?;
+    for key in &service_keys {
+        let targets_key = format!("prometheus_sd:{}:targets", key);
+        let labels_key = format!("prometheus_sd:{}:labels", key);
+        let targets: HashSet<String> = con.smembers(&targets_key)?;
+        if targets.is_empty() {
+            continue;
+        }
+        let live = filter_live_targets(con, key, targets.clone())?;
+        let expired: Vec<&String> = targets.iter().filter(|t| !live.contains(*t)).collect();
+        if expired.is_empty() {
+            continue;
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for target in &expired {
+            pipe.srem(&targets_key, *target);
+        }
+        if expired.len() == targets.len() {
+            pipe.del(&targets_key);
+            pipe.del(&labels_key);
+            pipe.srem(SERVICE_KEY, key);
+        }
+        pipe.query(con)?;
+    }
+    Ok(())
+}
+
+/// Discover the current services and serialize them to pretty-printed JSON.
+fn dump_services_json(con: &mut redis::Connection) -> Result<Vec<u8>> {
+    let services = discover_services(con)?;
+    Ok(serde_json::to_vec_pretty(&services)?)
+}
+
+/// Write already-serialized service JSON to `out_path`.
+fn write_services(out_path: &Path, body: &[u8]) -> Result<()> {
+    io::Write::write_all(
+        &mut io::BufWriter::with_capacity(256 * 1024, File::create(out_path)?),
+        body,
+    )?;
+    Ok(())
 }
 
 /// Monitor Redis for changes to the service registry and dump the new service definition on a change.
+///
+/// Runs forever, re-establishing both the data and pubsub connections with
+/// the same backoff policy as the initial connect whenever the underlying
+/// Redis link drops (e.g. on a Redis restart), so a long-running `discover`
+/// process survives transient outages instead of crashing on the first one.
 fn monitor_registry(
+    client: &redis::Client,
+    out_path: &Path,
+    max_timeout_sec: u64,
+    debounce: Duration,
+    ha_lock: Option<&LeaderLock>,
+    db: i64,
+) -> Result<()> {
+    // Whether we currently hold the HA lock, carried across reconnects so a
+    // transient Redis blip doesn't make us think we lost leadership (see
+    // `update_leadership`). Irrelevant, and always true, when HA is disabled.
+    let mut is_leader = ha_lock.is_none();
+    loop {
+        if let Err(e) = monitor_registry_session(
+            client,
+            out_path,
+            max_timeout_sec,
+            debounce,
+            ha_lock,
+            db,
+            &mut is_leader,
+        ) {
+            warn!(
+                "Lost connection to Redis while monitoring registry ({}), reconnecting...",
+                e
+            );
+        }
+    }
+}
+
+/// Refresh our hold on `ha_lock` if we already had it, or attempt to claim it
+/// otherwise, updating `is_leader` in place. Returns whether we held the lock
+/// going in. A no-op that always returns (and leaves) `true` when HA is
+/// disabled.
+///
+/// Refreshing rather than blindly re-claiming matters across a reconnect: a
+/// blind `try_claim` (`SET ... NX`) fails whenever our own, not-yet-expired
+/// lock entry is still sitting in Redis from before the blip, which would
+/// wrongly read as having lost leadership to a non-existent contender.
+fn update_leadership(
+    ha_lock: Option<&LeaderLock>,
     con: &mut redis::Connection,
+    is_leader: &mut bool,
+) -> Result<bool> {
+    let was_leader = *is_leader;
+    if let Some(lock) = ha_lock {
+        *is_leader = if was_leader {
+            lock.refresh(con)?
+        } else {
+            lock.try_claim(con)?
+        };
+    }
+    Ok(was_leader)
+}
+
+/// Run a single monitoring session, for as long as the pubsub connection stays up.
+///
+/// A single service registration can emit several keyspace events in a row
+/// (e.g. `sadd`, `hset`, `hset_multiple`, `sadd`), so rather than rescanning
+/// and rewriting the output file on every message, we drain all events that
+/// are already queued up once the first one arrives, and only rescan once
+/// `debounce` has passed without a new one. The rescan result is also
+/// compared against the last one written, so a quiet burst that didn't
+/// actually change anything doesn't churn the file Prometheus is watching.
+///
+/// When `ha_lock` is set, only the replica currently holding the lock writes
+/// the output file; the others keep `discover_services` warm in the
+/// background and take over writing once they win the lock after the
+/// current leader's TTL lapses.
+fn monitor_registry_session(
     client: &redis::Client,
     out_path: &Path,
+    max_timeout_sec: u64,
+    debounce: Duration,
+    ha_lock: Option<&LeaderLock>,
+    db: i64,
+    is_leader: &mut bool,
 ) -> Result<()> {
+    let mut con = try_redis_connect(client, max_timeout_sec)?;
     // We need a dedicated connection for the pubsub, since the pubsub struct
     // has a mutable reference to the connection as a member and we need to run
-    // commands on the connection as well. No retries for this connection, we
-    // assume that this will succeed, given that we already have established
-    // a live connection.
-    let mut pubsub_con = client.get_connection()?;
+    // commands on the connection as well.
+    let mut pubsub_con = try_redis_connect(client, max_timeout_sec)?;
     // Enable keyspace event notifications
     redis::cmd("CONFIG")
         .arg("SET")
@@ -168,17 +486,118 @@ fn monitor_registry(
         .arg("Ksh") // Only get notified for keyspace events on set and hash keys
         .query(&mut pubsub_con)?;
     let mut pubsub = pubsub_con.as_pubsub();
-    pubsub.psubscribe("__keyspace@0__:prometheus_sd*")?;
+    // Match the DB the commands actually run against, since Redis keyspace
+    // notifications are scoped per-database.
+    pubsub.psubscribe(format!("__keyspace@{}__:prometheus_sd*", db))?;
+
+    // Renew (not blindly re-claim) our leadership across this reconnect.
+    update_leadership(ha_lock, &mut con, is_leader)?;
+
+    // We may have missed events while (re)connecting, so do a full reconcile
+    // dump before resuming to watch for further changes.
+    let mut last_written = dump_services_json(&mut con)?;
+    if *is_leader {
+        write_services(out_path, &last_written)?;
+    }
+
+    // We wake up periodically even without any keyspace events, both to
+    // refresh (or attempt to claim) the HA lock before it expires, and to
+    // reap any targets whose TTL marker lapsed without a keyspace event of
+    // its own (e.g. a missed heartbeat).
+    let reap_interval = Duration::from_secs(5);
+    let poll_interval = Some(
+        ha_lock
+            .map(|lock| Duration::from_millis(lock.ttl_ms / 3))
+            .unwrap_or(reap_interval),
+    );
+
     loop {
-        let _ = pubsub.get_message()?;
-        let services = discover_services(con)?;
-        serde_json::to_writer_pretty(
-            io::BufWriter::with_capacity(256 * 1024, File::create(out_path)?),
-            &services,
-        )?;
+        pubsub.set_read_timeout(poll_interval)?;
+        match pubsub.get_message() {
+            Ok(_) => {
+                // Then switch to a short, non-blocking drain: keep consuming
+                // whatever's already queued up until the quiet window elapses.
+                pubsub.set_read_timeout(Some(debounce))?;
+                loop {
+                    match pubsub.get_message() {
+                        Ok(_) => continue,
+                        Err(e) if e.is_timeout() => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            Err(e) if e.is_timeout() => {} // periodic tick, nothing to drain
+            Err(e) => return Err(e.into()),
+        }
+
+        prune_expired_targets(&mut con)?;
+
+        let was_leader = update_leadership(ha_lock, &mut con, is_leader)?;
+
+        let body = dump_services_json(&mut con)?;
+        if *is_leader && (!was_leader || body != last_written) {
+            write_services(out_path, &body)?;
+            last_written = body;
+        }
     }
 }
 
+/// Serve the discovered targets over HTTP, for Prometheus' `http_sd_config`.
+///
+/// Every request to `path` re-queries Redis via `discover_services` and
+/// returns the result as JSON, so the scraper and discoverer never need to
+/// share a filesystem. A Redis failure is reported to the client as a 500
+/// with the `CliError` message, rather than crashing the server.
+fn serve_targets(
+    redis_client: &redis::Client,
+    max_timeout_sec: u64,
+    listen_addr: &str,
+    path: &str,
+) -> Result<()> {
+    let server = tiny_http::Server::http(listen_addr).map_err(|e| CliError::ServerError {
+        message: e.to_string(),
+    })?;
+    info!(
+        "Serving discovered targets at http://{}{}",
+        listen_addr, path
+    );
+    let mut con = try_redis_connect(redis_client, max_timeout_sec)?;
+    for request in server.incoming_requests() {
+        let response = if request.url() != path {
+            tiny_http::Response::from_string("Not Found").with_status_code(404)
+        } else {
+            match discover_services(&mut con)
+                .and_then(|services| Ok(serde_json::to_string(&services)?))
+            {
+                Ok(body) => {
+                    let content_type = tiny_http::Header::from_bytes(
+                        &b"Content-Type"[..],
+                        &b"application/json"[..],
+                    )
+                    .unwrap();
+                    tiny_http::Response::from_string(body).with_header(content_type)
+                }
+                Err(e) => {
+                    error!("Failed to discover services: {}, reconnecting...", e);
+                    // Bounded, not `max_timeout_sec`: this runs inline in the
+                    // single-threaded request loop, so a long backoff here
+                    // would stall every client for the duration of the outage
+                    // instead of just 500ing the affected requests.
+                    match try_redis_connect(redis_client, SHORT_RECONNECT_TIMEOUT_SEC) {
+                        Ok(new_con) => con = new_con,
+                        Err(e) => warn!("Failed to reconnect to Redis: {}", e),
+                    }
+                    tiny_http::Response::from_string(e.to_string()).with_status_code(500)
+                }
+            }
+        };
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to write HTTP response: {}", e);
+        }
+    }
+    Ok(())
+}
+
 /// Try connecting to Redis and retry until a given maximum timeout is reached.
 fn try_redis_connect(
     redis_client: &redis::Client,
@@ -227,13 +646,21 @@ fn run_app(matches: &ArgMatches) -> Result<()> {
         .unwrap_or("redis://localhost:6379");
     let max_timeout_sec: u64 = matches.value_of("max-timeout").unwrap_or("28800").parse()?;
     info!("Connecting to {}", redis_url);
-    let redis_client = match redis::Client::open(redis_url) {
+    let mut redis_client = match redis::Client::open(redis_url) {
         Ok(client) => client,
         Err(e) => {
             error!("Error connecting to Redis: {}", e);
             return Err(CliError::RedisError { source: e });
         }
     };
+    // The `--db` flag, if given, overrides whatever database index is
+    // already selected by `redis-url` (e.g. the `3` in `redis://host/3`).
+    if let Some(db) = matches.value_of("db") {
+        let mut info = redis_client.get_connection_info().clone();
+        info.redis.db = db.parse()?;
+        redis_client = redis::Client::open(info)?;
+    }
+    let db = redis_client.get_connection_info().redis.db;
     match matches.subcommand() {
         Some(("register", sub_matches)) => {
             let service_key = sub_matches.value_of("SERVICE_KEY").unwrap();
@@ -258,8 +685,9 @@ fn run_app(matches: &ArgMatches) -> Result<()> {
                     .unwrap_or("/metrics")
                     .to_owned(),
             };
+            let ttl: Option<u64> = sub_matches.value_of("ttl").map(str::parse).transpose()?;
             let mut redis_conn = try_redis_connect(&redis_client, max_timeout_sec)?;
-            register_instance(&mut redis_conn, &service)?;
+            register_instance(&mut redis_conn, &service, ttl)?;
             Ok(())
         }
         Some(("unregister", sub_matches)) => {
@@ -271,13 +699,85 @@ fn run_app(matches: &ArgMatches) -> Result<()> {
         }
         Some(("discover", sub_matches)) => {
             let out_path = Path::new(sub_matches.value_of("output").unwrap());
-            // Write out the initial service definitions before starting to watch
-            // for changes. This is so that we have a valid set of discovered targets
-            // at all times, even when initially deploying the app.
-            let mut con = try_redis_connect(&redis_client, max_timeout_sec)?;
-            let services = discover_services(&mut con)?;
-            serde_json::to_writer_pretty(File::create(out_path)?, &services)?;
-            monitor_registry(&mut con, &redis_client, out_path)?;
+            let debounce_ms: u64 = sub_matches
+                .value_of("debounce-ms")
+                .unwrap_or("250")
+                .parse::<u64>()?
+                .max(MIN_DEBOUNCE_MS);
+            // When running redundant replicas for HA, only the one holding
+            // the leader lock is allowed to write the output file.
+            let ha_lock = if sub_matches.is_present("ha-lock") {
+                let lock_ttl_ms: u64 = sub_matches
+                    .value_of("lock-ttl-ms")
+                    .unwrap_or("15000")
+                    .parse::<u64>()?
+                    .max(MIN_LOCK_TTL_MS);
+                let lock = LeaderLock::new(lock_ttl_ms);
+                let release_lock = lock.clone();
+                let release_client = redis_client.clone();
+                ctrlc::set_handler(move || {
+                    // Connect fresh rather than reusing a connection opened at
+                    // startup: `monitor_registry` may have long since
+                    // reconnected past whatever that connection saw, and a
+                    // stale one here would silently fail to release the lock.
+                    if let Ok(mut con) =
+                        try_redis_connect(&release_client, SHORT_RECONNECT_TIMEOUT_SEC)
+                    {
+                        let _ = release_lock.release(&mut con);
+                    }
+                    process::exit(0);
+                })
+                .map_err(|e| CliError::SignalHandlerError {
+                    message: e.to_string(),
+                })?;
+                Some(lock)
+            } else {
+                None
+            };
+            // `monitor_registry` writes out the initial service definitions
+            // itself before starting to watch for changes, so that we have a
+            // valid set of discovered targets at all times, even when
+            // initially deploying the app.
+            monitor_registry(
+                &redis_client,
+                out_path,
+                max_timeout_sec,
+                Duration::from_millis(debounce_ms),
+                ha_lock.as_ref(),
+                db,
+            )?;
+            Ok(())
+        }
+        Some(("serve", sub_matches)) => {
+            let listen_addr = sub_matches.value_of("listen").unwrap_or("0.0.0.0:8080");
+            let path = sub_matches.value_of("path").unwrap_or("/targets");
+            serve_targets(&redis_client, max_timeout_sec, listen_addr, path)?;
+            Ok(())
+        }
+        Some(("heartbeat", sub_matches)) => {
+            let service_key = sub_matches.value_of("SERVICE_KEY").unwrap();
+            let target = format!(
+                "{}:{}{}",
+                sub_matches.value_of("host").unwrap(),
+                sub_matches.value_of("port").unwrap().parse::<u16>()?,
+                sub_matches.value_of("metrics-path").unwrap_or("/metrics"),
+            );
+            let ttl: u64 = sub_matches.value_of("ttl").unwrap().parse()?;
+            let interval = Duration::from_secs(
+                sub_matches
+                    .value_of("interval")
+                    .map(str::parse)
+                    .transpose()?
+                    .unwrap_or(ttl / 3),
+            );
+            heartbeat(
+                &redis_client,
+                max_timeout_sec,
+                service_key,
+                &target,
+                ttl,
+                interval,
+            )?;
             Ok(())
         }
         _ => Ok(()), // Should not happen, since clap will exit before
@@ -301,6 +801,9 @@ fn main() {
                 .takes_value(true),
             arg!(-t --"max-timeout" [NUMBER] "Maximum timeout in seconds to try initially connecting to Redis (default 28800 = 8 hours)")
                 .env("PROMETHEUS_SD_REDIS_TIMEOUT")
+                .takes_value(true),
+            arg!(--db [NUMBER] "Redis database index to use, overriding any index already selected by --redis-url")
+                .env("PROMETHEUS_SD_REDIS_DB")
                 .takes_value(true)
         ])
         .subcommand(App::new("register")
@@ -319,7 +822,8 @@ fn main() {
                             .value_names(&["KEY", "VALUE"]),
                         arg!(-m --"metrics-path" [TEXT]    "Metrics path for the service. Defaults to /metrics."),
                         arg!(-h --host <TEXT>              "Hostname for the service."),
-                        arg!(-p --port <INTEGER>           "Port the metrics are exported at.")
+                        arg!(-p --port <INTEGER>           "Port the metrics are exported at."),
+                        arg!(-t --ttl [NUMBER]              "Expire this registration after this many seconds unless refreshed with 'heartbeat'.")
 
                     ]))
         .subcommand(App::new("unregister")
@@ -334,7 +838,33 @@ fn main() {
                     .display_order(3)
                     .about("Discover services in the environment.")
                     .long_about(DISCOVER_HELP)
-                    .args(&[arg!(-o --output <FILE> "File to write the service definitions to")]));
+                    .args(&[
+                        arg!(-o --output <FILE>            "File to write the service definitions to"),
+                        arg!(-d --"debounce-ms" [NUMBER]   "Quiet window in milliseconds to wait for a burst of registry changes to settle before rescanning (default 250, minimum 1)"),
+                        arg!(--"ha-lock"                    "Use a Redis-based leader lock so only one of several redundant replicas writes the output file"),
+                        arg!(--"lock-ttl-ms" [NUMBER]      "TTL in milliseconds for the HA leader lock, refreshed every ttl/3 (default 15000)"),
+                    ]))
+        .subcommand(App::new("serve")
+                    .display_order(4)
+                    .about("Serve discovered targets over HTTP.")
+                    .long_about(SERVE_HELP)
+                    .mut_arg("help", |h| h.short('?'))
+                    .args(&[
+                        arg!(-l --listen [ADDRESS] "Address to listen on (default '0.0.0.0:8080')"),
+                        arg!(-p --path [TEXT]      "URL path to serve the targets at (default '/targets')"),
+                    ]))
+        .subcommand(App::new("heartbeat")
+                    .display_order(5)
+                    .about("Periodically refresh a '--ttl' registration so it doesn't expire.")
+                    .mut_arg("help", |h| h.short('?'))
+                    .args(&[
+                        arg!(<SERVICE_KEY>                 "Sets the service key to use"),
+                        arg!(-m --"metrics-path" [TEXT]    "Metrics path for the service. Defaults to /metrics."),
+                        arg!(-h --host <TEXT>              "Hostname for the service."),
+                        arg!(-p --port <INTEGER>           "Port the metrics are exported at."),
+                        arg!(-t --ttl <NUMBER>              "TTL in seconds to refresh the registration with."),
+                        arg!(-i --"interval" [NUMBER]       "Interval in seconds to send heartbeats at. Defaults to ttl/3."),
+                    ]));
     let matches = app.get_matches();
     match run_app(&matches) {
         Ok(_) => {}